@@ -2,25 +2,61 @@ use chrono::prelude::*;
 use csv::Reader;
 use futures::future::join_all;
 use serde::de::DeserializeOwned;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use structopt::StructOpt;
+
+use std::collections::BTreeMap;
 use std::env;
 use std::fmt::{self, Debug};
-use std::fs::{remove_file, File};
-use std::io::{self, Cursor, Write};
+use std::fs::{read_dir, read_to_string, remove_file, File};
+use std::io::{self, Cursor, Read, Write};
 use std::num::ParseFloatError;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::thread;
 
+/// Top-level shape of the external `sources.toml` config: a list of `[[source]]` tables.
+#[derive(Debug, Deserialize)]
+struct Config {
+    source: Vec<Source>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
-#[serde(rename_all = "PascalCase")]
 struct Source {
     id: u32,
     url: String,
+    /// The ordered extraction recipe applied to the downloaded page. Each step chomps its way to
+    /// a value which is parsed with `extract_pence` and stored in the step's target field.
+    #[serde(default, rename = "step")]
+    steps: Vec<ExtractStep>,
 }
 
+/// A single step in a source's extraction recipe: chomp through `markers` in order, then parse the
+/// text up to the next tag into `field`.
 #[derive(Debug, Deserialize, Clone)]
+struct ExtractStep {
+    field: Field,
+    markers: Vec<String>,
+}
+
+/// The `Price` field an extraction step's value maps to. `change` is not stored directly; it is
+/// subtracted from the price to recover `prev_price`, matching the original hardcoded behaviour.
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Field {
+    Price,
+    Change,
+    #[serde(rename = "52w_high")]
+    FiftyTwoWeekHigh,
+    #[serde(rename = "52w_low")]
+    FiftyTwoWeekLow,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "PascalCase")]
 struct Stock {
     id: u32,
@@ -30,7 +66,7 @@ struct Stock {
     enabled: bool,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 struct Price {
     stock_id: u32,
@@ -38,9 +74,15 @@ struct Price {
     date: Date<Utc>,
     price: f32,
     prev_price: f32,
-    #[serde(deserialize_with = "deserialize_optional")]
+    #[serde(
+        serialize_with = "serialize_optional",
+        deserialize_with = "deserialize_optional"
+    )]
     fifty_two_week_high: Option<f32>,
-    #[serde(deserialize_with = "deserialize_optional")]
+    #[serde(
+        serialize_with = "serialize_optional",
+        deserialize_with = "deserialize_optional"
+    )]
     fifty_two_week_low: Option<f32>,
 }
 
@@ -95,54 +137,400 @@ impl StringExtensions for String {
     }
 }
 
+/// Downloads UK stock prices and writes files for Quicken and my 'shares' spreadsheet.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "stock_prices")]
+struct Opt {
+    /// The directory into which the output files are written. Defaults to the current directory.
+    #[structopt(long, parse(from_os_str))]
+    output_dir: Option<PathBuf>,
+
+    /// The TOML config describing the download sources and their extraction recipes.
+    #[structopt(long, parse(from_os_str), default_value = "sources.toml")]
+    config: PathBuf,
+
+    /// The divisor applied to the scraped pence price when writing the Quicken file.
+    #[structopt(long, default_value = "100.0")]
+    factor: f32,
+
+    /// The backing format for the master price history store. Binary (bincode) avoids the
+    /// per-field CSV deserialize overhead once the history grows large.
+    #[structopt(long, default_value = "csv")]
+    format: Format,
+
+    /// Restrict processing to these stock symbols. Defaults to all enabled stocks.
+    #[structopt(long)]
+    symbols: Vec<String>,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Download today's prices and write the output files. This is the default.
+    Download,
+    /// Print the enabled stocks and their resolved source URLs without downloading anything.
+    List,
+    /// Re-run just the stocks that failed on the previous run, read back from errors.txt.
+    OnlyErrors,
+    /// Re-emit qp.csv/stockdata.csv from the master history store (history.csv, or history.bin
+    /// with `--format bin`) for a date window rather than only today's prices.
+    Range {
+        /// Inclusive lower bound as an RFC-3339 timestamp. Defaults to the start of history.
+        #[structopt(long)]
+        start: Option<String>,
+        /// Inclusive upper bound as an RFC-3339 timestamp. Defaults to the end of history.
+        #[structopt(long)]
+        end: Option<String>,
+    },
+    /// Roll the day's stockdata.csv into a compressed, deduplicated per-year archive.
+    Archive {
+        /// Regenerate the archive from the existing yearly archives plus all present daily files,
+        /// instead of merging only today's file.
+        #[structopt(long)]
+        rebuild: bool,
+    },
+}
+
+/// The backing format for the master price history store.
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Csv,
+    Bin,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "bin" => Ok(Format::Bin),
+            other => Err(format!("Unknown format '{}', expected 'csv' or 'bin'", other)),
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    // You may pass 1 or more stock symbols on the command line
-    // to filter to just those stocks.
-    let requested_stocks = std::env::args()
-        .skip(1)
-        .map(|a| a.to_string())
-        .collect::<Vec<_>>();
-
-    // These data files are embedded into the binary, meaning we do not need to ship them as
-    // supporting files (but if anything changes, we need to rebuild the program.)
-    let download_sources = include_bytes!("data/source.csv");
-    let stocks = include_bytes!("data/stock.csv");
+    let opt = Opt::from_args();
 
-    // Turn the embedded byte arrays into more reasonable data structures.
-    let mut cursor = Cursor::new(&download_sources[..]);
-    let download_sources: Vec<Source> = read_csv(&mut cursor).expect("Could not read source.csv");
+    let output_dir = opt.output_dir.clone().unwrap_or_else(|| {
+        env::current_dir()
+            .expect("Could not determine current directory, so cannot write any output")
+    });
 
+    let download_sources = load_sources(&opt.config);
+    let stocks = load_stocks();
+
+    match opt.cmd.unwrap_or(Command::Download) {
+        Command::Download => {
+            let stocks = select_stocks(stocks, &opt.symbols);
+            run_download(&output_dir, &stocks, &download_sources, opt.factor, opt.format).await;
+        }
+        Command::List => {
+            let stocks = select_stocks(stocks, &opt.symbols);
+            for stock in &stocks {
+                let source = find_source(&download_sources, stock);
+                println!("{}\t{}{}", stock.symbol, source.url, stock.digital_look_name);
+            }
+        }
+        Command::OnlyErrors => {
+            let failed = read_failed_symbols(&output_dir).expect("Could not read errors.txt");
+            if failed.is_empty() {
+                println!("No previous errors to re-download.");
+                return;
+            }
+            let stocks = select_stocks(stocks, &failed);
+            run_download(&output_dir, &stocks, &download_sources, opt.factor, opt.format).await;
+        }
+        Command::Range { start, end } => {
+            let start = start.as_deref().map(parse_rfc3339_date);
+            let end = end.as_deref().map(parse_rfc3339_date);
+
+            let prices = read_history(&output_dir, opt.format)
+                .expect("Could not read the history store")
+                .into_iter()
+                .filter(|p| start.map_or(true, |s| p.date >= s))
+                .filter(|p| end.map_or(true, |e| p.date <= e))
+                .collect::<Vec<_>>();
+
+            println!("Writing output files for {} historical prices.", prices.len());
+            write_qp_csv(&output_dir, &prices, &stocks, opt.factor)
+                .expect("Could not write Quicken prices file.");
+            write_stockdata_csv(&output_dir, &prices, &stocks)
+                .expect("Could not write Stock prices file (for shares.ods).");
+        }
+        Command::Archive { rebuild } => {
+            if rebuild {
+                rebuild_archive(&output_dir).expect("Could not rebuild archive.");
+            } else {
+                archive_stockdata(&output_dir).expect("Could not archive stockdata.csv.");
+            }
+        }
+    }
+}
+
+/// Parses an RFC-3339 timestamp down to the `Date<Utc>` it falls on. A bare `YYYY-MM-DD` is also
+/// accepted — it is treated as midnight UTC on that day — since the range window is conceptually a
+/// date, not an instant.
+fn parse_rfc3339_date(s: &str) -> Date<Utc> {
+    let normalised = if s.len() == 10 {
+        format!("{}T00:00:00Z", s)
+    } else {
+        s.to_string()
+    };
+    DateTime::parse_from_rfc3339(&normalised)
+        .unwrap_or_else(|e| panic!("Could not parse '{}' as an RFC-3339 date: {}", s, e))
+        .with_timezone(&Utc)
+        .date()
+}
+
+/// Reads the external `sources.toml` config into a `Vec<Source>`. Sources used to be embedded via
+/// `include_bytes!`, which meant a markup change on a scraped site required a recompile; the
+/// extraction recipes now live in config so new sites are added purely in TOML.
+fn load_sources(path: &Path) -> Vec<Source> {
+    let toml = read_to_string(path)
+        .unwrap_or_else(|e| panic!("Could not read sources config {:?}: {}", path, e));
+    let config: Config =
+        toml::from_str(&toml).unwrap_or_else(|e| panic!("Could not parse {:?}: {}", path, e));
+    for source in &config.source {
+        if source.steps.is_empty() {
+            panic!(
+                "Source {} in {:?} has an empty extraction recipe; every stock using it would scrape zero prices",
+                source.id, path
+            );
+        }
+    }
+    config.source
+}
+
+/// Reads the embedded `stock.csv` into a `Vec<Stock>`, sorted ascending by symbol.
+fn load_stocks() -> Vec<Stock> {
+    let stocks = include_bytes!("data/stock.csv");
     let mut cursor = Cursor::new(&stocks[..]);
     let mut stocks: Vec<Stock> = read_csv(&mut cursor).expect("Could not read stock.csv");
     stocks.sort_by(|a, b| a.symbol.cmp(&b.symbol));
-    let stocks = if requested_stocks.is_empty() {
-        stocks
-            .into_iter()
-            .filter(|stk| stk.enabled)
-            .collect::<Vec<_>>()
+    stocks
+}
+
+/// Filters the master stock list down to the set we want to process. If no symbols are requested
+/// we keep only the enabled stocks; otherwise we keep exactly the named symbols.
+fn select_stocks(stocks: Vec<Stock>, requested: &[String]) -> Vec<Stock> {
+    if requested.is_empty() {
+        stocks.into_iter().filter(|stk| stk.enabled).collect()
     } else {
         stocks
             .into_iter()
-            .filter(|stk| requested_stocks.iter().any(|rs| rs == &stk.symbol))
-            .collect::<Vec<_>>()
-    };
+            .filter(|stk| requested.iter().any(|rs| rs == &stk.symbol))
+            .collect()
+    }
+}
+
+/// Finds the `Source` a `Stock` is downloaded from, panicking if the data files are inconsistent.
+fn find_source<'a>(sources: &'a [Source], stock: &Stock) -> &'a Source {
+    sources
+        .iter()
+        .find(|s| s.id == stock.source_id)
+        .unwrap_or_else(|| panic!("Cannot find Source for Stock {}", stock.symbol))
+}
 
+/// Downloads the prices for `stocks` and writes the Quicken, spreadsheet and error files.
+async fn run_download(
+    output_dir: &Path,
+    stocks: &[Stock],
+    sources: &[Source],
+    factor: f32,
+    format: Format,
+) {
     println!(
         "Data files read successfully. Beginning download of {} prices.",
         stocks.len()
     );
 
-    let (new_prices, errors) = download_prices2(&stocks, &download_sources).await;
+    let (new_prices, errors) = download_prices2(stocks, sources).await;
 
     println!("Writing output files.");
-    let output_dir = env::current_dir()
-        .expect("Could not determine current directory, so cannot write any output");
-    write_qp_csv(&output_dir, &new_prices, &stocks, 100.0)
+    write_qp_csv(output_dir, &new_prices, stocks, factor)
         .expect("Could not write Quicken prices file.");
-    write_stockdata_csv(&output_dir, &new_prices, &stocks)
+    write_stockdata_csv(output_dir, &new_prices, stocks)
         .expect("Could not write Stock prices file (for shares.ods).");
-    write_errors(&output_dir, &errors).expect("Could not write errors file.");
+    write_postgres_csv(output_dir, &new_prices, stocks)
+        .expect("Could not write Postgres COPY file.");
+    append_history(output_dir, &new_prices, format).expect("Could not update the history store.");
+    write_errors(output_dir, &errors).expect("Could not write errors file.");
+}
+
+/// Returns the path of the master price history store for the given backing `format`.
+fn history_path(output_dir: &Path, format: Format) -> PathBuf {
+    let mut path = output_dir.to_path_buf();
+    path.push(match format {
+        Format::Csv => "history.csv",
+        Format::Bin => "history.bin",
+    });
+    path
+}
+
+/// Reads the master price history in the given backing `format`, returning an empty vec if it
+/// does not yet exist.
+fn read_history(output_dir: &Path, format: Format) -> io::Result<Vec<Price>> {
+    match format {
+        Format::Csv => read_history_csv(output_dir),
+        Format::Bin => read_history_bin(output_dir),
+    }
+}
+
+/// Writes the master price history in the given backing `format`.
+fn write_history(output_dir: &Path, prices: &[Price], format: Format) -> io::Result<()> {
+    match format {
+        Format::Csv => write_history_csv(output_dir, prices),
+        Format::Bin => write_history_bin(output_dir, prices),
+    }
+}
+
+/// Merges freshly-downloaded prices into the master history store, de-duplicating by
+/// `(stock_id, date)` so that re-running on the same day is idempotent, and keeping the store
+/// sorted ascending by date so that range queries are a simple scan.
+fn append_history(output_dir: &Path, new_prices: &[Price], format: Format) -> io::Result<()> {
+    if new_prices.is_empty() {
+        return Ok(());
+    }
+
+    let mut history = read_history(output_dir, format)?;
+    for price in new_prices {
+        match history
+            .iter_mut()
+            .find(|p| p.stock_id == price.stock_id && p.date == price.date)
+        {
+            Some(existing) => *existing = price.clone(),
+            None => history.push(price.clone()),
+        }
+    }
+    history.sort_by(|a, b| a.date.cmp(&b.date).then(a.stock_id.cmp(&b.stock_id)));
+
+    write_history(output_dir, &history, format)
+}
+
+/// Reads the CSV-backed history, returning an empty vec if it does not yet exist.
+fn read_history_csv(output_dir: &Path) -> io::Result<Vec<Price>> {
+    let path = history_path(output_dir, Format::Csv);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let bytes = std::fs::read(&path)?;
+    let mut cursor = Cursor::new(&bytes[..]);
+    read_csv(&mut cursor)
+}
+
+/// Writes the CSV-backed history in the same shape that `Price` deserializes from, so it can be
+/// read straight back via `read_history_csv`.
+fn write_history_csv(output_dir: &Path, prices: &[Price]) -> io::Result<()> {
+    let path = history_path(output_dir, Format::Csv);
+    let mut file = File::create(&path)?;
+    writeln!(
+        file,
+        "StockId,Date,Price,PrevPrice,FiftyTwoWeekHigh,FiftyTwoWeekLow"
+    )?;
+
+    for price in prices {
+        writeln!(
+            file,
+            "{},{},{},{},{},{}",
+            price.stock_id,
+            price.date.and_hms(0, 0, 0).format(my_date_format::FORMAT),
+            price.price,
+            price.prev_price,
+            price
+                .fifty_two_week_high
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+            price
+                .fifty_two_week_low
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Reads the binary-backed history: a little-endian `u64` record count followed by that many
+/// length-prefixed `bincode` records. Returns an empty vec if the store does not yet exist.
+fn read_history_bin(output_dir: &Path) -> io::Result<Vec<Price>> {
+    let path = history_path(output_dir, Format::Bin);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let bytes = std::fs::read(&path)?;
+    let mut offset = 0;
+    let count = read_u64(&bytes, &mut offset)? as usize;
+    let mut prices = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = read_u64(&bytes, &mut offset)? as usize;
+        let end = offset + len;
+        let slice = bytes.get(offset..end).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "history.bin record is truncated")
+        })?;
+        prices.push(bincode::deserialize(slice).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+        offset = end;
+    }
+
+    Ok(prices)
+}
+
+/// Writes the binary-backed history as a little-endian `u64` record count followed by that many
+/// length-prefixed `bincode` records. The file is written to a temporary path and atomically
+/// renamed into place so that a crash mid-write cannot corrupt the store.
+fn write_history_bin(output_dir: &Path, prices: &[Price]) -> io::Result<()> {
+    let path = history_path(output_dir, Format::Bin);
+    let tmp = path.with_extension("bin.tmp");
+
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(&(prices.len() as u64).to_le_bytes())?;
+        for price in prices {
+            let bytes =
+                bincode::serialize(price).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            file.write_all(&(bytes.len() as u64).to_le_bytes())?;
+            file.write_all(&bytes)?;
+        }
+        file.sync_all()?;
+    }
+
+    std::fs::rename(&tmp, &path)
+}
+
+/// Reads a little-endian `u64` from `bytes` starting at `offset`, advancing `offset` past it.
+fn read_u64(bytes: &[u8], offset: &mut usize) -> io::Result<u64> {
+    let end = *offset + 8;
+    let slice = bytes
+        .get(*offset..end)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "history.bin is truncated"))?;
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(slice);
+    *offset = end;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Reads back the symbols that failed on a previous run from `errors.txt`. Each line is written as
+/// `SYMBOL: message`, so the symbol is simply the text up to the first colon.
+fn read_failed_symbols(output_dir: &Path) -> io::Result<Vec<String>> {
+    let mut path = output_dir.to_path_buf();
+    path.push("errors.txt");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let contents = read_to_string(&path)?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| line.split(':').next())
+        .map(|symbol| symbol.trim().to_string())
+        .filter(|symbol| !symbol.is_empty())
+        .collect())
 }
 
 fn read_csv<T: Debug + DeserializeOwned>(rdr: &mut Cursor<&[u8]>) -> std::io::Result<Vec<T>> {
@@ -160,29 +548,29 @@ async fn download_prices2(stocks: &[Stock], sources: &[Source]) -> (Vec<Price>,
     let mut prices = Vec::with_capacity(stocks.len());
     let mut errors = Vec::new();
     let mut tasks = Vec::with_capacity(stocks.len());
+    let mut symbols = Vec::with_capacity(stocks.len());
 
     for stock in stocks {
-        let source = sources
-            .iter()
-            .find(|s| s.id == stock.source_id)
-            .expect(&format!("Cannot find Source for Stock {}", stock.symbol))
-            .clone();
+        let source = find_source(sources, stock).clone();
 
         let stock = stock.clone();
+        symbols.push(stock.symbol.clone());
         tasks.push(tokio::spawn(
             async move { download_price(stock, source).await },
         ));
     }
 
     let completed_tasks = join_all(tasks).await;
-    
-    for t in completed_tasks {
+
+    // join_all preserves task order, so we can recover the symbol each result belongs to. Errors
+    // are keyed by symbol (`SYMBOL: message`) so that `only-errors` can re-run just those stocks.
+    for (symbol, t) in symbols.into_iter().zip(completed_tasks) {
         match t {
             Ok(r) => match r {
                 Ok(price) => prices.push(price),
-                Err(e) => errors.push(format!("Could not download price, error is {}", e)),
+                Err(e) => errors.push(format!("{}: {}", symbol, e)),
             },
-            Err(e) => errors.push(format!("Could not download price, error is {}", e)),
+            Err(e) => errors.push(format!("{}: {}", symbol, e)),
         }
     }
 
@@ -216,55 +604,22 @@ async fn download_price(stock: Stock, source: Source) -> Result<Price, StockPric
         fifty_two_week_low: None,
     };
 
-    if source.id == 1 {
-        // A Digital Look equity.
-        body.chomp("Market Data</h2>")?;
-        body.chomp("precio_ultima_cotizacion")?;
-        body.chomp(">")?;
-        price.price = extract_pence(&body)?;
-        //println!("  Got price of {}", price.price);
-
-        body.chomp("variacion_puntos")?;
-        body.chomp(">")?;
-        body.chomp(">")?;
-        let price_change_today = extract_pence(&body)?;
-        price.prev_price = price.price - price_change_today;
-        //println!("  Got price_change_today of {}", price_change_today);
-
-        body.chomp("High 52 week range")?;
-        body.chomp("<td>")?;
-        price.fifty_two_week_high = Some(extract_pence(&body)?);
-        //println!("  Got 52 week high of {:?}", price.fifty_two_week_high);
-
-        body.chomp("Low 52 week range")?;
-        body.chomp("<td>")?;
-        price.fifty_two_week_low = Some(extract_pence(&body)?);
-        //println!("  Got 52 week low of {:?}", price.fifty_two_week_low);
-    } else if source.id == 2 {
-        // A Digital Look ETF.
-        body.chomp("Detailed Price Data</h2>")?;
-        body.chomp("<td>Price:</td>")?;
-        body.chomp(">")?;
-        price.price = extract_pence(&body)?;
-        //println!("  Got price of {}", price.price);
-
-        body.chomp("<td>Change:</td>")?;
-        body.chomp("<td>")?;
-        body.chomp(">")?;
-        let price_change_today = extract_pence(&body)?;
-        price.prev_price = price.price - price_change_today;
-        //println!("  Got price_change_today of {}", price_change_today);
-
-        body.chomp("52 week High")?;
-        body.chomp("<td>")?;
-        price.fifty_two_week_high = Some(extract_pence(&body)?);
-        //println!("  Got 52 week high of {:?}", price.fifty_two_week_high);
-
-        body.chomp("52 week Low")?;
-        body.chomp("<td>")?;
-        price.fifty_two_week_low = Some(extract_pence(&body)?);
-        //println!("  Got 52 week low of {:?}", price.fifty_two_week_low);
+    // Walk the declarative recipe for this source. Adding a new site, or adapting to a markup
+    // change, is now a config edit rather than a new `if source.id == N` branch in here.
+    let mut price_change_today = 0.0;
+    for step in &source.steps {
+        for marker in &step.markers {
+            body.chomp(marker)?;
+        }
+        let value = extract_pence(&body)?;
+        match step.field {
+            Field::Price => price.price = value,
+            Field::Change => price_change_today = value,
+            Field::FiftyTwoWeekHigh => price.fifty_two_week_high = Some(value),
+            Field::FiftyTwoWeekLow => price.fifty_two_week_low = Some(value),
+        }
     }
+    price.prev_price = price.price - price_change_today;
 
     //println!("GOT {:#?}", price);
 
@@ -368,6 +723,213 @@ fn write_stockdata_csv(output_dir: &Path, prices: &[Price], stocks: &[Stock]) ->
     Ok(())
 }
 
+/// Writes a tab-delimited file ready to be ingested into Postgres via `COPY ... FROM`. The columns
+/// are emitted in a stable order (`stock_id, symbol, date, price, prev_price, fifty_two_week_high,
+/// fifty_two_week_low`), dates are formatted as ISO-8601, and the optional 52-week fields are
+/// rendered as the `COPY` NULL token `\N` when absent rather than `0.0`, preserving the genuine
+/// distinction between "missing" and "zero" that `deserialize_optional` captures.
+fn write_postgres_csv(output_dir: &Path, prices: &[Price], stocks: &[Stock]) -> io::Result<()> {
+    let mut path = output_dir.to_path_buf();
+    path.push("postgres.csv");
+    delete_file(&path)?;
+
+    if prices.len() > 0 {
+        println!("\nWriting {:?}", path);
+        let mut file = File::create(&path)?;
+
+        for price in prices {
+            let stock = stocks
+                .iter()
+                .find(|s| s.id == price.stock_id)
+                .expect("Could not find Stock the Price is for.");
+            writeln!(
+                file,
+                "{}\t{}\t{:04}-{:02}-{:02}\t{:.2}\t{:.2}\t{}\t{}",
+                price.stock_id,
+                stock.symbol,
+                price.date.year(),
+                price.date.month(),
+                price.date.day(),
+                price.price,
+                price.prev_price,
+                format_copy_optional(price.fifty_two_week_high),
+                format_copy_optional(price.fifty_two_week_low),
+            )?;
+        }
+
+        println!("Succeeded in writing {:?}", path);
+    }
+
+    Ok(())
+}
+
+/// Renders an optional price as a 2dp value, or the `COPY` NULL token `\N` when it is absent.
+fn format_copy_optional(value: Option<f32>) -> String {
+    match value {
+        Some(v) => format!("{:.2}", v),
+        None => "\\N".to_string(),
+    }
+}
+
+/// Returns the path of the gzip archive holding `stockdata.csv` rows for a given year.
+fn archive_path(output_dir: &Path, year: i32) -> PathBuf {
+    let mut path = output_dir.to_path_buf();
+    path.push(format!("stockdata-{}.csv.gz", year));
+    path
+}
+
+/// Parses a `stockdata.csv` row (`symbol,price,dd/mm/yyyy,prev`) into its year and de-duplication
+/// key. The `(symbol, date)` pair uniquely identifies a stock-day, mirroring the `(stock_id, date)`
+/// key used by the history store.
+fn parse_stockdata_key(row: &str) -> io::Result<(i32, (String, String))> {
+    let fields: Vec<&str> = row.split(',').collect();
+    if fields.len() < 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Malformed stockdata row: {}", row),
+        ));
+    }
+    let date = fields[2].to_string();
+    let year = date
+        .rsplit('/')
+        .next()
+        .and_then(|y| y.parse::<i32>().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Cannot read year from stockdata row: {}", row),
+            )
+        })?;
+    Ok((year, (fields[0].to_string(), date)))
+}
+
+/// Reads a yearly archive into a map keyed by `(symbol, date)`, returning an empty map if it does
+/// not yet exist.
+fn read_archive_year(output_dir: &Path, year: i32) -> io::Result<BTreeMap<(String, String), String>> {
+    let path = archive_path(output_dir, year);
+    let mut rows = BTreeMap::new();
+    if !path.exists() {
+        return Ok(rows);
+    }
+
+    let mut decoder = GzDecoder::new(File::open(&path)?);
+    let mut contents = String::new();
+    decoder.read_to_string(&mut contents)?;
+    for row in contents.lines() {
+        if row.trim().is_empty() {
+            continue;
+        }
+        let (_, key) = parse_stockdata_key(row)?;
+        rows.insert(key, row.to_string());
+    }
+
+    Ok(rows)
+}
+
+/// Writes a yearly archive as gzip-compressed `stockdata.csv` rows, ordered by key.
+fn write_archive_year(
+    output_dir: &Path,
+    year: i32,
+    rows: &BTreeMap<(String, String), String>,
+) -> io::Result<()> {
+    let path = archive_path(output_dir, year);
+    let mut encoder = GzEncoder::new(File::create(&path)?, Compression::default());
+    for row in rows.values() {
+        writeln!(encoder, "{}", row)?;
+    }
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Merges `rows` into the per-year archives, de-duplicating by `(symbol, date)`. When
+/// `preserve_existing` is set the archive already on disk wins, so re-running on a day that is
+/// already archived is a no-op; otherwise (a rebuild) the archives are regenerated from scratch.
+fn merge_into_archive<'a>(
+    output_dir: &Path,
+    rows: impl Iterator<Item = &'a str>,
+    preserve_existing: bool,
+) -> io::Result<usize> {
+    let mut by_year: BTreeMap<i32, Vec<((String, String), String)>> = BTreeMap::new();
+    for row in rows {
+        if row.trim().is_empty() {
+            continue;
+        }
+        let (year, key) = parse_stockdata_key(row)?;
+        by_year.entry(year).or_default().push((key, row.to_string()));
+    }
+
+    let mut added = 0;
+    for (year, new_rows) in by_year {
+        let mut merged = if preserve_existing {
+            read_archive_year(output_dir, year)?
+        } else {
+            BTreeMap::new()
+        };
+        for (key, row) in new_rows {
+            if !merged.contains_key(&key) {
+                merged.insert(key, row);
+                added += 1;
+            }
+        }
+        write_archive_year(output_dir, year, &merged)?;
+    }
+
+    Ok(added)
+}
+
+/// Rolls the current day's `stockdata.csv` into the compressed per-year archive.
+fn archive_stockdata(output_dir: &Path) -> io::Result<()> {
+    let mut daily = output_dir.to_path_buf();
+    daily.push("stockdata.csv");
+    if !daily.exists() {
+        println!("No stockdata.csv to archive.");
+        return Ok(());
+    }
+
+    let contents = read_to_string(&daily)?;
+    let added = merge_into_archive(output_dir, contents.lines(), true)?;
+    println!("Archived {} new rows.", added);
+    Ok(())
+}
+
+/// Regenerates the archive from every present source — the existing `stockdata-YYYY.csv.gz`
+/// archives plus the current `stockdata.csv` and any dated `stockdata-*.csv` siblings. The already
+/// archived rows are ingested first so a rebuild never discards history; loose daily files only add
+/// stock-days not already present.
+fn rebuild_archive(output_dir: &Path) -> io::Result<()> {
+    let mut all = String::new();
+    let mut daily = String::new();
+    for entry in read_dir(output_dir)? {
+        let path = entry?.path();
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        if name.starts_with("stockdata-") && name.ends_with(".csv.gz") {
+            let year = name
+                .trim_start_matches("stockdata-")
+                .trim_end_matches(".csv.gz")
+                .parse::<i32>();
+            if let Ok(year) = year {
+                for row in read_archive_year(output_dir, year)?.into_values() {
+                    all.push_str(&row);
+                    all.push('\n');
+                }
+            }
+        } else if name.starts_with("stockdata") && name.ends_with(".csv") {
+            daily.push_str(&read_to_string(&path)?);
+            if !daily.ends_with('\n') {
+                daily.push('\n');
+            }
+        }
+    }
+    all.push_str(&daily);
+
+    let added = merge_into_archive(output_dir, all.lines(), false)?;
+    println!("Rebuilt archive with {} rows.", added);
+    Ok(())
+}
+
 fn write_errors(output_dir: &Path, errors: &[String]) -> io::Result<()> {
     let mut path = output_dir.to_path_buf();
     path.push("errors.txt");
@@ -398,6 +960,20 @@ fn delete_file(path: &Path) -> io::Result<()> {
     Ok(())
 }
 
+/// The serialize counterpart to `deserialize_optional`: renders the value as a string (empty for
+/// `None`) so that a `Price` round-trips through any string-oriented format identically to the CSV
+/// representation it was first read from.
+fn serialize_optional<S, T>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+    T: fmt::Display,
+{
+    match value {
+        Some(v) => serializer.serialize_str(&v.to_string()),
+        None => serializer.serialize_str(""),
+    }
+}
+
 fn deserialize_optional<'de, D, T>(de: D) -> Result<Option<T>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -424,9 +1000,19 @@ where
 
 mod my_date_format {
     use chrono::{Date, Datelike, TimeZone, Utc};
-    use serde::{self, Deserialize, Deserializer};
+    use serde::{self, Deserialize, Deserializer, Serializer};
+
+    pub const FORMAT: &'static str = "%Y-%m-%d %H:%M:%S.%3f";
 
-    const FORMAT: &'static str = "%Y-%m-%d %H:%M:%S.%3f";
+    // The signature of a serialize_with function mirrors deserialize: it receives the value plus
+    // the serializer. We render midnight of the date so the output matches the on-disk CSV format.
+    pub fn serialize<S>(date: &Date<Utc>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let s = date.and_hms(0, 0, 0).format(FORMAT).to_string();
+        serializer.serialize_str(&s)
+    }
 
     // The signature of a deserialize_with function must follow the pattern:
     //
@@ -448,7 +1034,10 @@ mod my_date_format {
 
 #[cfg(test)]
 mod tests {
-    use crate::StringExtensions;
+    use crate::{read_history_bin, read_history_csv, write_history_bin, write_history_csv};
+    use crate::{Price, StringExtensions};
+    use chrono::{TimeZone, Utc};
+    use std::fs::create_dir_all;
 
     #[test]
     fn chomp_when_pattern_exists_returns_following_text() {
@@ -465,6 +1054,74 @@ mod tests {
         assert_eq!(s, "");
     }
 
+    #[test]
+    fn csv_and_binary_history_decode_to_identical_prices() {
+        let prices = vec![
+            Price {
+                stock_id: 1,
+                date: Utc.ymd(2020, 1, 2),
+                price: 123.5,
+                prev_price: 119.0,
+                fifty_two_week_high: Some(130.75),
+                fifty_two_week_low: Some(90.5),
+            },
+            Price {
+                stock_id: 2,
+                date: Utc.ymd(2020, 1, 3),
+                price: 12.25,
+                prev_price: 12.0,
+                fifty_two_week_high: None,
+                fifty_two_week_low: None,
+            },
+        ];
+
+        let dir = std::env::temp_dir().join(format!("stock_prices_test_{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+
+        write_history_csv(&dir, &prices).unwrap();
+        write_history_bin(&dir, &prices).unwrap();
+
+        let from_csv = read_history_csv(&dir).unwrap();
+        let from_bin = read_history_bin(&dir).unwrap();
+
+        assert_eq!(from_csv, prices);
+        assert_eq!(from_csv, from_bin);
+    }
+
+    #[test]
+    fn sources_toml_parses_with_non_empty_recipes() {
+        use crate::Config;
+
+        let toml = std::fs::read_to_string("sources.toml").unwrap();
+        let config: Config = toml::from_str(&toml).unwrap();
+
+        assert!(!config.source.is_empty(), "expected at least one source");
+        for source in &config.source {
+            assert!(
+                !source.steps.is_empty(),
+                "source {} decoded with an empty recipe",
+                source.id
+            );
+        }
+    }
+
+    #[test]
+    fn archiving_the_same_day_twice_yields_one_record_per_stock() {
+        use crate::{merge_into_archive, read_archive_year};
+
+        let dir =
+            std::env::temp_dir().join(format!("stock_prices_archive_test_{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let _ = std::fs::remove_file(dir.join("stockdata-2020.csv.gz"));
+
+        let day = "AAA,100.00,02/01/2020,99.00\nBBB,200.00,02/01/2020,199.00";
+        merge_into_archive(&dir, day.lines(), true).unwrap();
+        merge_into_archive(&dir, day.lines(), true).unwrap();
+
+        let archived = read_archive_year(&dir, 2020).unwrap();
+        assert_eq!(archived.len(), 2);
+    }
+
     #[test]
     fn chomp_when_pattern_does_not_exist_returns_error() {
         let mut s = "hello world".to_string();